@@ -1,20 +1,73 @@
+use metrics::{Key, Unit};
+use std::collections::BTreeMap;
+
+/// Identifies a metric by name plus its sorted label set.
+///
+/// This mirrors how the `metrics` crate itself identifies a distribution: two
+/// `Key`s with the same name but different labels are distinct series and
+/// must not be aggregated together.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MetricKey {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl MetricKey {
+    pub fn from_key(key: &Key) -> Self {
+        let labels = key
+            .labels()
+            .map(|label| (label.key().to_string(), label.value().to_string()))
+            .collect();
+        Self {
+            name: key.name().to_string(),
+            labels,
+        }
+    }
+}
+
 pub enum CounterCmd {
-    Increment { name: String, value: u64 },
-    Absolute { name: String, value: u64 },
+    Increment { key: MetricKey, value: u64 },
+    Absolute { key: MetricKey, value: u64 },
 }
 
 pub enum GaugeCmd {
-    Increment { name: String, value: f64 },
-    Decrement { name: String, value: f64 },
-    Set { name: String, value: f64 },
+    Increment { key: MetricKey, value: f64 },
+    Decrement { key: MetricKey, value: f64 },
+    Set { key: MetricKey, value: f64 },
 }
 
 pub enum HistogramCmd {
-    Record { name: String, value: f64 },
+    Record { key: MetricKey, value: f64 },
+}
+
+/// Unit and human-readable description supplied via `metrics::describe_*!`,
+/// keyed by metric name (units and descriptions aren't per-label).
+pub struct DescribeCmd {
+    pub name: String,
+    pub unit: Option<Unit>,
+    pub description: String,
 }
 
 pub enum MetricsCmd {
     Counter(CounterCmd),
     Gauge(GaugeCmd),
     Histogram(HistogramCmd),
+    Describe(DescribeCmd),
+}
+
+impl MetricsCmd {
+    /// The metric this command updates, used to track recency for idle
+    /// expiry. `None` for `Describe`, which carries metadata rather than a
+    /// value update.
+    pub(crate) fn key(&self) -> Option<&MetricKey> {
+        match self {
+            MetricsCmd::Counter(CounterCmd::Increment { key, .. })
+            | MetricsCmd::Counter(CounterCmd::Absolute { key, .. })
+            | MetricsCmd::Gauge(GaugeCmd::Increment { key, .. })
+            | MetricsCmd::Gauge(GaugeCmd::Decrement { key, .. })
+            | MetricsCmd::Gauge(GaugeCmd::Set { key, .. })
+            | MetricsCmd::Histogram(HistogramCmd::Record { key, .. }) => Some(key),
+            MetricsCmd::Describe(_) => None,
+        }
+    }
 }
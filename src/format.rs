@@ -0,0 +1,404 @@
+//! Log serialization: turns a snapshot of tracked metrics into the bytes
+//! handed to the logging callback.
+//!
+//! `LogSerializer` is a trait so alternate encodings can be slotted in
+//! alongside the two built in here: the crate's JSON document and the
+//! Prometheus text exposition format.
+
+use crate::state::TRACKED_QUANTILES;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Selects which `LogSerializer` `MetricsState` renders a log emission with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A single well-formed JSON document per log emission.
+    Json,
+    /// Prometheus text exposition format.
+    Prometheus,
+}
+
+pub(crate) fn serializer_for(format: OutputFormat) -> Box<dyn LogSerializer> {
+    match format {
+        OutputFormat::Json => Box::new(JsonSerializer),
+        OutputFormat::Prometheus => Box::new(PrometheusSerializer),
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct CounterRecord {
+    pub(crate) name: String,
+    pub(crate) labels: BTreeMap<String, String>,
+    pub(crate) value: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct GaugeRecord {
+    pub(crate) name: String,
+    pub(crate) labels: BTreeMap<String, String>,
+    pub(crate) value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct HistogramRecord {
+    pub(crate) name: String,
+    pub(crate) labels: BTreeMap<String, String>,
+    pub(crate) avg: f64,
+    pub(crate) std_dev: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) samples: u64,
+    /// Quantile label (e.g. `"p50"`) to estimated value, per
+    /// `TRACKED_QUANTILES`. A label is absent if its estimator hasn't warmed
+    /// up yet (fewer than 5 samples recorded).
+    pub(crate) quantiles: BTreeMap<String, f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+}
+
+/// A point-in-time snapshot of the metrics a log emission covers, ready to
+/// be handed to a `LogSerializer`.
+#[derive(serde::Serialize, Default)]
+pub(crate) struct LogDocument {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) counters: Vec<CounterRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) gauges: Vec<GaugeRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) histograms: Vec<HistogramRecord>,
+}
+
+impl LogDocument {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.gauges.is_empty() && self.histograms.is_empty()
+    }
+}
+
+/// Renders a `LogDocument` to the string handed to the logging callback.
+pub(crate) trait LogSerializer {
+    fn serialize(&self, doc: &LogDocument) -> String;
+}
+
+/// Serializes a `LogDocument` as a single JSON document.
+pub(crate) struct JsonSerializer;
+
+impl LogSerializer for JsonSerializer {
+    fn serialize(&self, doc: &LogDocument) -> String {
+        serde_json::to_string(doc).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+/// Serializes a `LogDocument` as Prometheus text exposition format.
+///
+/// See <https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format>.
+/// This is a best-effort local-testing format: bucket counts are derived
+/// from the histogram's P² quantile estimates rather than true per-bucket
+/// counters, since `HistogramState` doesn't track fixed bucket boundaries.
+pub(crate) struct PrometheusSerializer;
+
+/// Computes the `(le, count)` pairs for a histogram's `_bucket` lines from
+/// its tracked quantiles, merging any that fall back to the same `le` (e.g.
+/// all of p50/p90/p99 before the P² warm-up completes) into a single bucket.
+///
+/// Prometheus requires exactly one timeseries per `le` value and
+/// non-decreasing counts as `le` increases, so colliding quantiles are
+/// folded together keeping the larger (more inclusive) count, and the
+/// result is sorted by `le` with a running max applied.
+fn histogram_buckets(histogram: &HistogramRecord) -> Vec<(f64, u64)> {
+    let mut buckets: Vec<(f64, u64)> = Vec::new();
+    for (label, fraction) in TRACKED_QUANTILES {
+        let raw_le = histogram.quantiles.get(label).copied().unwrap_or(histogram.max);
+        // Round to the same precision we render `le` at, so estimates that
+        // only differ beyond the displayed precision still collapse.
+        let le = (raw_le * 100.0).round() / 100.0;
+        let count = (histogram.samples as f64 * fraction).round() as u64;
+        match buckets.iter_mut().find(|(existing_le, _)| *existing_le == le) {
+            Some((_, existing_count)) => *existing_count = (*existing_count).max(count),
+            None => buckets.push((le, count)),
+        }
+    }
+    buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut running_max = 0u64;
+    for (_, count) in &mut buckets {
+        running_max = running_max.max(*count);
+        *count = running_max;
+    }
+    buckets
+}
+
+impl LogSerializer for PrometheusSerializer {
+    fn serialize(&self, doc: &LogDocument) -> String {
+        let mut out = String::new();
+
+        for counter in &doc.counters {
+            render_metadata(&mut out, &counter.name, &counter.unit, &counter.description);
+            let _ = writeln!(out, "# TYPE {} counter", counter.name);
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                counter.name,
+                render_labels(&counter.labels, None),
+                counter.value
+            );
+        }
+
+        for gauge in &doc.gauges {
+            render_metadata(&mut out, &gauge.name, &gauge.unit, &gauge.description);
+            let _ = writeln!(out, "# TYPE {} gauge", gauge.name);
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                gauge.name,
+                render_labels(&gauge.labels, None),
+                gauge.value
+            );
+        }
+
+        for histogram in &doc.histograms {
+            render_metadata(
+                &mut out,
+                &histogram.name,
+                &histogram.unit,
+                &histogram.description,
+            );
+            let _ = writeln!(out, "# TYPE {} histogram", histogram.name);
+            for (le, count) in histogram_buckets(histogram) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {count}",
+                    histogram.name,
+                    render_labels(&histogram.labels, Some(("le", format!("{le:.2}"))))
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                histogram.name,
+                render_labels(&histogram.labels, Some(("le", "+Inf".to_string()))),
+                histogram.samples
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{} {}",
+                histogram.name,
+                render_labels(&histogram.labels, None),
+                histogram.avg * histogram.samples as f64
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{} {}",
+                histogram.name,
+                render_labels(&histogram.labels, None),
+                histogram.samples
+            );
+        }
+
+        out
+    }
+}
+
+fn render_metadata(out: &mut String, name: &str, unit: &Option<String>, description: &Option<String>) {
+    if let Some(description) = description {
+        let _ = writeln!(out, "# HELP {name} {}", escape_help_text(description));
+    }
+    if let Some(unit) = unit {
+        let _ = writeln!(out, "# UNIT {name} {}", escape_help_text(unit));
+    }
+}
+
+/// Renders a label set as Prometheus label-matcher syntax, e.g.
+/// `{route="/a",method="GET"}`. Returns an empty string when there are no
+/// labels (including no trailing `le` pair).
+fn render_labels(labels: &BTreeMap<String, String>, extra: Option<(&str, String)>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!(r#"{}="{}""#, k, escape_label_value(v)))
+        .collect();
+    if let Some((key, value)) = extra {
+        pairs.push(format!(r#"{}="{}""#, key, escape_label_value(&value)));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Escapes a string for embedding as a quoted Prometheus label value, per
+/// the text exposition format spec: backslash, double quote, and newline
+/// must be escaped.
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Escapes a string for a `# HELP`/`# UNIT` comment line. Unlike label
+/// values, this text isn't quoted, so only backslash and newline need
+/// escaping.
+fn escape_help_text(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(name: &str, labels: &[(&str, &str)], value: u64) -> CounterRecord {
+        CounterRecord {
+            name: name.to_string(),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            value,
+            unit: None,
+            description: None,
+        }
+    }
+
+    fn histogram(labels: &[(&str, &str)], quantiles: &[(&str, f64)]) -> HistogramRecord {
+        HistogramRecord {
+            name: "latency".to_string(),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            avg: 2.0,
+            std_dev: 1.0,
+            min: 1.0,
+            max: 3.0,
+            samples: 3,
+            quantiles: quantiles.iter().map(|(l, v)| (l.to_string(), *v)).collect(),
+            unit: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_prometheus_serializer_renders_counter_with_labels() {
+        let doc = LogDocument {
+            counters: vec![counter("requests", &[("route", "/a")], 5)],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        assert!(out.contains("# TYPE requests counter"), "output was:\n{out}");
+        assert!(out.contains(r#"requests{route="/a"} 5"#), "output was:\n{out}");
+    }
+
+    #[test]
+    fn test_prometheus_serializer_escapes_label_values() {
+        let doc = LogDocument {
+            counters: vec![counter("requests", &[("route", "/a\"b\\c\nd")], 1)],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        assert!(
+            out.contains(r#"route="/a\"b\\c\nd""#),
+            "output was:\n{out}"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_serializer_emits_help_and_unit_before_type() {
+        let mut record = counter("bytes_sent", &[], 42);
+        record.unit = Some("bytes".to_string());
+        record.description = Some("total bytes sent".to_string());
+        let doc = LogDocument {
+            counters: vec![record],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        let help_idx = out.find("# HELP bytes_sent total bytes sent").unwrap();
+        let unit_idx = out.find("# UNIT bytes_sent bytes").unwrap();
+        let type_idx = out.find("# TYPE bytes_sent counter").unwrap();
+        assert!(help_idx < unit_idx && unit_idx < type_idx, "output was:\n{out}");
+    }
+
+    #[test]
+    fn test_prometheus_serializer_escapes_help_text() {
+        let mut record = counter("bytes_sent", &[], 1);
+        record.description = Some("line one\\two\nline three".to_string());
+        let doc = LogDocument {
+            counters: vec![record],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        assert!(
+            out.contains("# HELP bytes_sent line one\\\\two\\nline three"),
+            "output was:\n{out}"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_serializer_histogram_bucket_and_count() {
+        let doc = LogDocument {
+            histograms: vec![histogram(&[], &[("p50", 2.0), ("p90", 3.0), ("p99", 3.0)])],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        assert!(out.contains("# TYPE latency histogram"), "output was:\n{out}");
+        assert!(out.contains(r#"latency_bucket{le="2.00"} 2"#), "output was:\n{out}");
+        assert!(out.contains(r#"latency_bucket{le="+Inf"} 3"#), "output was:\n{out}");
+        assert!(out.contains("latency_count 3"), "output was:\n{out}");
+        assert!(out.contains("latency_sum 6"), "output was:\n{out}");
+    }
+
+    #[test]
+    fn test_prometheus_serializer_histogram_before_warmup_merges_duplicate_buckets() {
+        // Fewer than 5 samples means the P² estimator hasn't warmed up yet,
+        // so `quantiles` carries no entries for p50/p90/p99, and all three
+        // fall back to `max` — they must collapse into a single `le` line
+        // instead of three duplicate timeseries.
+        let doc = LogDocument {
+            histograms: vec![histogram(&[], &[])],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        let bucket_lines: Vec<&str> = out
+            .lines()
+            .filter(|line| line.starts_with("latency_bucket") && !line.contains("+Inf"))
+            .collect();
+        assert_eq!(
+            bucket_lines,
+            vec![r#"latency_bucket{le="3.00"} 3"#],
+            "output was:\n{out}"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_serializer_histogram_buckets_have_no_duplicate_le_with_differing_counts() {
+        let doc = LogDocument {
+            histograms: vec![histogram(&[], &[("p50", 2.0), ("p90", 3.0), ("p99", 3.0)])],
+            ..Default::default()
+        };
+        let out = PrometheusSerializer.serialize(&doc);
+        let mut seen: std::collections::HashMap<&str, &str> = std::collections::HashMap::new();
+        for line in out.lines().filter(|line| line.starts_with("latency_bucket")) {
+            let (matcher, count) = line.rsplit_once(' ').expect("bucket line has a count");
+            if let Some(&prior_count) = seen.get(matcher) {
+                assert_eq!(
+                    prior_count, count,
+                    "duplicate le {matcher} with differing counts, output was:\n{out}"
+                );
+            } else {
+                seen.insert(matcher, count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_serializer_emits_single_well_formed_document() {
+        let doc = LogDocument {
+            counters: vec![counter("requests", &[("route", "/a")], 5)],
+            ..Default::default()
+        };
+        let out = JsonSerializer.serialize(&doc);
+        let parsed: serde_json::Value = serde_json::from_str(&out).expect("should be valid JSON");
+        assert_eq!(parsed["counters"][0]["value"], 5);
+        assert_eq!(parsed["counters"][0]["labels"]["route"], "/a");
+    }
+}
@@ -3,7 +3,7 @@ use metrics::{CounterFn, GaugeFn, HistogramFn};
 use std::sync::mpsc::Sender;
 
 pub(crate) struct CounterHandle<F> {
-    pub(crate) name: String,
+    pub(crate) key: MetricKey,
     pub(crate) tx: Sender<MetricsCmd>,
     pub(crate) err_cb: F,
 }
@@ -14,7 +14,7 @@ where
 {
     fn increment(&self, value: u64) {
         if let Err(e) = self.tx.send(MetricsCmd::Counter(CounterCmd::Increment {
-            name: self.name.clone(),
+            key: self.key.clone(),
             value,
         })) {
             (self.err_cb)(&format!(
@@ -25,7 +25,7 @@ where
     }
     fn absolute(&self, value: u64) {
         if let Err(e) = self.tx.send(MetricsCmd::Counter(CounterCmd::Absolute {
-            name: self.name.clone(),
+            key: self.key.clone(),
             value,
         })) {
             (self.err_cb)(&format!(
@@ -36,7 +36,7 @@ where
     }
 }
 pub(crate) struct GaugeHandle<F> {
-    pub(crate) name: String,
+    pub(crate) key: MetricKey,
     pub(crate) tx: Sender<MetricsCmd>,
     pub(crate) err_cb: F,
 }
@@ -47,7 +47,7 @@ where
 {
     fn increment(&self, value: f64) {
         if let Err(e) = self.tx.send(MetricsCmd::Gauge(GaugeCmd::Increment {
-            name: self.name.clone(),
+            key: self.key.clone(),
             value,
         })) {
             (self.err_cb)(&format!(
@@ -59,7 +59,7 @@ where
 
     fn decrement(&self, value: f64) {
         if let Err(e) = self.tx.send(MetricsCmd::Gauge(GaugeCmd::Decrement {
-            name: self.name.clone(),
+            key: self.key.clone(),
             value,
         })) {
             (self.err_cb)(&format!(
@@ -71,7 +71,7 @@ where
 
     fn set(&self, value: f64) {
         if let Err(e) = self.tx.send(MetricsCmd::Gauge(GaugeCmd::Set {
-            name: self.name.clone(),
+            key: self.key.clone(),
             value,
         })) {
             (self.err_cb)(&format!("Failed to send gauge metrics for set: {:?}", e));
@@ -80,7 +80,7 @@ where
 }
 
 pub(crate) struct HistogramHandle<F> {
-    pub(crate) name: String,
+    pub(crate) key: MetricKey,
     pub(crate) tx: Sender<MetricsCmd>,
     pub(crate) err_cb: F,
 }
@@ -91,7 +91,7 @@ where
 {
     fn record(&self, value: f64) {
         if let Err(e) = self.tx.send(MetricsCmd::Histogram(HistogramCmd::Record {
-            name: self.name.clone(),
+            key: self.key.clone(),
             value,
         })) {
             (self.err_cb)(&format!("Failed to send histogram metrics: {:?}", e));
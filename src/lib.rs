@@ -14,11 +14,16 @@
 //!
 //! ## Example
 //! ```rust
-//! use metrics_logger::{metrics, MetricsLogger, LogMode};
+//! use metrics_logger::{metrics, MetricsLogger, LogMode, OutputFormat};
+//! use std::time::Duration;
 //!
 //! // MetricsLogger implements the Recorder trait
 //! let recorder = MetricsLogger::new(
-//!     LogMode::Periodic(10), // Logging interval in seconds
+//!     LogMode::Periodic {
+//!         interval_secs: 10,
+//!         idle_timeout: Duration::from_secs(300), // drop metrics idle this long
+//!     },
+//!     OutputFormat::Json,
 //!     |logs| println!("Metrics: {}", logs), // Logging callback
 //!     |err| eprintln!("Error: {}", err),    // Error callback
 //! );
@@ -29,7 +34,9 @@
 //!
 //! ## Modules
 //! - `cmd`: Handles commands for updating metrics.
+//! - `format`: Output formats (JSON, Prometheus text exposition) for logs.
 //! - `handles`: Implements metric handles (e.g., counters, gauges, histograms).
+//! - `quantile`: Online quantile estimation (P²) for histograms.
 //! - `state`: Manages metric state and generates logs.
 //!
 //! ## Dependencies
@@ -39,9 +46,13 @@
 pub use metrics;
 
 mod cmd;
+mod format;
 mod handles;
+mod quantile;
 mod state;
 
+pub use format::OutputFormat;
+
 use cmd::*;
 use handles::*;
 use state::*;
@@ -60,48 +71,77 @@ pub enum LogMode {
     /// Emit logs as soon as a metric is updated
     Immediate,
     /// Aggregate metrics for the specified duration, in seconds, before emitting a log
-    Periodic(u64),
+    Periodic {
+        interval_secs: u64,
+        /// Metrics not updated within this long are dropped from state
+        /// instead of being re-logged every interval.
+        idle_timeout: Duration,
+    },
+}
+
+/// Selects how a log emission is rendered: just the metrics that changed
+/// since the last emission (`Diff`), or a full dump of all tracked state
+/// (`Full`).
+pub enum PeriodicMode {
+    Diff,
+    Full,
 }
 
 impl<F> MetricsLogger<F>
 where
     F: Fn(&str) + Copy + Send + Sync + 'static,
 {
-    pub fn new<F2>(mode: LogMode, log_cb: F2, err_cb: F) -> Self
+    pub fn new<F2>(mode: LogMode, format: OutputFormat, log_cb: F2, err_cb: F) -> Self
     where
         F2: Fn(&str) + Copy + Send + Sync + 'static,
     {
         let (tx, rx) = mpsc::channel();
         match mode {
-            LogMode::Immediate => Self::launch_immediate_mode(rx, log_cb),
-            LogMode::Periodic(log_interval_secs) => {
-                Self::launch_periodic_mode(rx, log_cb, log_interval_secs)
-            }
+            LogMode::Immediate => Self::launch_immediate_mode(rx, format, log_cb),
+            LogMode::Periodic {
+                interval_secs,
+                idle_timeout,
+            } => Self::launch_periodic_mode(rx, format, log_cb, interval_secs, idle_timeout),
         }
         Self { tx, err_cb }
     }
 
-    fn launch_immediate_mode<F2>(rx: Receiver<MetricsCmd>, log_cb: F2)
+    fn describe(&self, name: KeyName, unit: Option<Unit>, description: SharedString) {
+        if let Err(e) = self.tx.send(MetricsCmd::Describe(DescribeCmd {
+            name: name.as_str().to_string(),
+            unit,
+            description: description.into_owned(),
+        })) {
+            (self.err_cb)(&format!("Failed to send describe command: {:?}", e));
+        }
+    }
+
+    fn launch_immediate_mode<F2>(rx: Receiver<MetricsCmd>, format: OutputFormat, log_cb: F2)
     where
         F2: Fn(&str) + Copy + Send + Sync + 'static,
     {
         std::thread::spawn(move || {
-            let mut state = MetricsState::new();
+            let mut state = MetricsState::new(format, None);
             for cmd in rx.iter() {
                 state.update(cmd);
-                if let Some(logs) = state.output_logs() {
+                if let Some(logs) = state.output_logs(PeriodicMode::Diff) {
                     (log_cb)(&logs);
                 }
             }
         });
     }
 
-    fn launch_periodic_mode<F2>(rx: Receiver<MetricsCmd>, log_cb: F2, log_interval_secs: u64)
-    where
+    fn launch_periodic_mode<F2>(
+        rx: Receiver<MetricsCmd>,
+        format: OutputFormat,
+        log_cb: F2,
+        log_interval_secs: u64,
+        idle_timeout: Duration,
+    ) where
         F2: Fn(&str) + Copy + Send + Sync + 'static,
     {
         std::thread::spawn(move || {
-            let mut state = MetricsState::new();
+            let mut state = MetricsState::new(format, Some(idle_timeout));
             let interval = Duration::from_secs(log_interval_secs);
             let mut next_log_time = Instant::now() + interval;
             loop {
@@ -115,7 +155,7 @@ where
 
                 let now = Instant::now();
                 if now >= next_log_time {
-                    if let Some(logs) = state.output_logs() {
+                    if let Some(logs) = state.output_logs(PeriodicMode::Full) {
                         (log_cb)(&logs);
                     }
                     next_log_time = now + interval;
@@ -129,16 +169,21 @@ impl<F> Recorder for MetricsLogger<F>
 where
     F: Fn(&str) + Copy + Send + Sync + 'static,
 {
-    fn describe_counter(&self, _name: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_counter(&self, name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(name, unit, description);
+    }
 
-    fn describe_gauge(&self, _name: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_gauge(&self, name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(name, unit, description);
+    }
 
-    fn describe_histogram(&self, _name: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_histogram(&self, name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(name, unit, description);
+    }
 
     fn register_counter(&self, key: &Key, _meta: &Metadata<'_>) -> Counter {
-        let name = key.name().to_string();
         let handle = CounterHandle {
-            name,
+            key: MetricKey::from_key(key),
             tx: self.tx.clone(),
             err_cb: self.err_cb,
         };
@@ -146,10 +191,8 @@ where
     }
 
     fn register_gauge(&self, key: &Key, _meta: &Metadata<'_>) -> Gauge {
-        let name = key.name().to_string();
-
         let handle = GaugeHandle {
-            name,
+            key: MetricKey::from_key(key),
             tx: self.tx.clone(),
             err_cb: self.err_cb,
         };
@@ -157,9 +200,8 @@ where
     }
 
     fn register_histogram(&self, key: &Key, _meta: &Metadata<'_>) -> Histogram {
-        let name = key.name().to_string();
         let handle = HistogramHandle {
-            name,
+            key: MetricKey::from_key(key),
             tx: self.tx.clone(),
             err_cb: self.err_cb,
         };
@@ -0,0 +1,153 @@
+//! Online quantile estimation using the P² ("P-square") algorithm.
+//!
+//! P² tracks a target quantile with five markers in O(1) memory per
+//! quantile, without buffering the underlying samples. See Jain & Chlamtac,
+//! "The P² Algorithm for Dynamic Calculation of Quantiles and Histograms
+//! Without Storing Observations" (1985).
+
+/// Tracks a single quantile `p` via the P² algorithm.
+#[derive(Clone, Debug)]
+pub(crate) struct P2Quantile {
+    p: f64,
+    /// Marker heights.
+    q: [f64; 5],
+    /// Actual marker positions.
+    n: [i64; 5],
+    /// Desired marker positions.
+    np: [f64; 5],
+    /// Per-observation increments to the desired positions.
+    dn: [f64; 5],
+    /// The first five samples, used only to drive the five-sample warm-up.
+    init_buffer: Vec<f64>,
+}
+
+impl P2Quantile {
+    pub(crate) fn new(p: f64) -> Self {
+        Self {
+            p,
+            q: [0.0; 5],
+            n: [0; 5],
+            np: [0.0; 5],
+            dn: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+            init_buffer: Vec::with_capacity(5),
+        }
+    }
+
+    pub(crate) fn update(&mut self, x: f64) {
+        if self.init_buffer.len() < 5 {
+            self.init_buffer.push(x);
+            if self.init_buffer.len() == 5 {
+                self.init_buffer
+                    .sort_by(|a, b| a.partial_cmp(b).expect("quantile samples must not be NaN"));
+                for i in 0..5 {
+                    self.q[i] = self.init_buffer[i];
+                    self.n[i] = (i + 1) as i64;
+                }
+                self.np = [
+                    1.0,
+                    1.0 + 2.0 * self.p,
+                    1.0 + 4.0 * self.p,
+                    3.0 + 2.0 * self.p,
+                    5.0,
+                ];
+            }
+            return;
+        }
+
+        let x = if x < self.q[0] {
+            self.q[0] = x;
+            x
+        } else if x > self.q[4] {
+            self.q[4] = x;
+            x
+        } else {
+            x
+        };
+
+        let k = (0..4)
+            .find(|&i| self.q[i] <= x && x < self.q[i + 1])
+            .unwrap_or(3);
+
+        for n in self.n.iter_mut().skip(k + 1) {
+            *n += 1;
+        }
+        for i in 0..5 {
+            self.np[i] += self.dn[i];
+        }
+
+        for i in 1..4 {
+            let d = self.np[i] - self.n[i] as f64;
+            if (d >= 1.0 && self.n[i + 1] - self.n[i] > 1)
+                || (d <= -1.0 && self.n[i - 1] - self.n[i] < -1)
+            {
+                let sign = if d >= 0.0 { 1.0 } else { -1.0 };
+                let parabolic = self.parabolic(i, sign);
+                self.q[i] = if self.q[i - 1] < parabolic && parabolic < self.q[i + 1] {
+                    parabolic
+                } else {
+                    self.linear(i, sign)
+                };
+                self.n[i] += sign as i64;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: usize, sign: f64) -> f64 {
+        let n = self.n.map(|v| v as f64);
+        let q = self.q;
+        let term1 = (n[i] - n[i - 1] + sign) * (q[i + 1] - q[i]) / (n[i + 1] - n[i]);
+        let term2 = (n[i + 1] - n[i] - sign) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]);
+        q[i] + sign * (term1 + term2) / (n[i + 1] - n[i - 1])
+    }
+
+    fn linear(&self, i: usize, sign: f64) -> f64 {
+        let j = (i as f64 + sign) as usize;
+        self.q[i] + sign * (self.q[j] - self.q[i]) / (self.n[j] - self.n[i]) as f64
+    }
+
+    pub(crate) fn p(&self) -> f64 {
+        self.p
+    }
+
+    /// Returns the current estimate, or `None` until at least 5 samples have
+    /// been observed.
+    pub(crate) fn estimate(&self) -> Option<f64> {
+        if self.init_buffer.len() < 5 {
+            None
+        } else {
+            Some(self.q[2])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_p2_quantile_empty() {
+        let q = P2Quantile::new(0.5);
+        assert_eq!(q.estimate(), None);
+    }
+
+    #[test]
+    fn test_p2_quantile_median_converges() {
+        let mut q = P2Quantile::new(0.5);
+        for i in 1..=1000 {
+            q.update(i as f64);
+        }
+        // The true median of 1..=1000 is 500.5; P² is an approximation.
+        let estimate = q.estimate().unwrap();
+        assert!((estimate - 500.5).abs() < 25.0, "estimate was {estimate}");
+    }
+
+    #[test]
+    fn test_p2_quantile_p99_converges() {
+        let mut q = P2Quantile::new(0.99);
+        for i in 1..=1000 {
+            q.update(i as f64);
+        }
+        let estimate = q.estimate().unwrap();
+        assert!((estimate - 990.0).abs() < 25.0, "estimate was {estimate}");
+    }
+}
@@ -1,133 +1,275 @@
 use crate::PeriodicMode;
 use crate::cmd::*;
-use std::collections::{HashMap, HashSet};
+use crate::format::{self, CounterRecord, GaugeRecord, HistogramRecord, LogDocument, LogSerializer, OutputFormat};
+use crate::quantile::P2Quantile;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Quantiles tracked per histogram, and the label used for each in logs.
+pub(crate) const TRACKED_QUANTILES: [(&str, f64); 3] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)];
 
-#[derive(Default)]
 pub struct MetricsState {
-    counter_state: HashMap<String, u64>,
-    gauge_state: HashMap<String, i64>,
-    histogram_state: HashMap<String, HistogramState>,
+    serializer: Box<dyn LogSerializer>,
+    /// How long a metric may go unupdated before it's dropped from state.
+    /// `None` (used in `Immediate` mode) means metrics never expire.
+    idle_timeout: Option<Duration>,
+
+    counter_state: HashMap<MetricKey, u64>,
+    gauge_state: HashMap<MetricKey, f64>,
+    histogram_state: HashMap<MetricKey, HistogramState>,
+
+    counter_updates: HashSet<MetricKey>,
+    gauge_updates: HashSet<MetricKey>,
+    histogram_updates: HashSet<MetricKey>,
+
+    last_updated: HashMap<MetricKey, Instant>,
+
+    descriptions: HashMap<String, Description>,
+}
 
-    counter_updates: HashSet<String>,
-    gauge_updates: HashSet<String>,
-    histogram_updates: HashSet<String>,
+/// Unit and human-readable description for a metric name, as supplied via
+/// `metrics::describe_*!`.
+#[derive(Clone, Default)]
+struct Description {
+    unit: Option<metrics::Unit>,
+    description: String,
 }
 
 impl MetricsState {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(format: OutputFormat, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            serializer: format::serializer_for(format),
+            idle_timeout,
+            counter_state: HashMap::new(),
+            gauge_state: HashMap::new(),
+            histogram_state: HashMap::new(),
+            counter_updates: HashSet::new(),
+            gauge_updates: HashSet::new(),
+            histogram_updates: HashSet::new(),
+            last_updated: HashMap::new(),
+            descriptions: HashMap::new(),
+        }
     }
 
     pub fn update(&mut self, cmd: MetricsCmd) {
+        if let Some(key) = cmd.key() {
+            self.last_updated.insert(key.clone(), Instant::now());
+        }
         match cmd {
             MetricsCmd::Counter(counter_cmd) => match counter_cmd {
-                CounterCmd::Increment { name, value } => {
-                    *self.counter_state.entry(name.clone()).or_insert(0) += value;
-                    self.counter_updates.insert(name);
+                CounterCmd::Increment { key, value } => {
+                    *self.counter_state.entry(key.clone()).or_insert(0) += value;
+                    self.counter_updates.insert(key);
                 }
-                CounterCmd::Absolute { name, value } => {
-                    self.counter_state.insert(name.clone(), value);
-                    self.counter_updates.insert(name);
+                CounterCmd::Absolute { key, value } => {
+                    self.counter_state.insert(key.clone(), value);
+                    self.counter_updates.insert(key);
                 }
             },
             MetricsCmd::Gauge(gauge_cmd) => match gauge_cmd {
-                GaugeCmd::Increment { name, value } => {
-                    *self.gauge_state.entry(name.clone()).or_insert(0) += value as i64;
-                    self.gauge_updates.insert(name);
+                GaugeCmd::Increment { key, value } => {
+                    *self.gauge_state.entry(key.clone()).or_insert(0.0) += value;
+                    self.gauge_updates.insert(key);
                 }
-                GaugeCmd::Decrement { name, value } => {
-                    *self.gauge_state.entry(name.clone()).or_insert(0) -= value as i64;
-                    self.gauge_updates.insert(name);
+                GaugeCmd::Decrement { key, value } => {
+                    *self.gauge_state.entry(key.clone()).or_insert(0.0) -= value;
+                    self.gauge_updates.insert(key);
                 }
-                GaugeCmd::Set { name, value } => {
-                    self.gauge_state.insert(name.clone(), value as i64);
-                    self.gauge_updates.insert(name);
+                GaugeCmd::Set { key, value } => {
+                    self.gauge_state.insert(key.clone(), value);
+                    self.gauge_updates.insert(key);
                 }
             },
             MetricsCmd::Histogram(histogram_cmd) => match histogram_cmd {
-                HistogramCmd::Record { name, value } => {
+                HistogramCmd::Record { key, value } => {
                     self.histogram_state
-                        .entry(name.clone())
+                        .entry(key.clone())
                         .and_modify(|x| x.update(value))
                         .or_default();
 
-                    self.histogram_updates.insert(name);
+                    self.histogram_updates.insert(key);
                 }
             },
+            MetricsCmd::Describe(describe_cmd) => {
+                self.descriptions.insert(
+                    describe_cmd.name,
+                    Description {
+                        unit: describe_cmd.unit,
+                        description: describe_cmd.description,
+                    },
+                );
+            }
         }
     }
 
     pub fn output_logs(&mut self, mode: PeriodicMode) -> Option<String> {
+        self.purge_idle();
         match mode {
             PeriodicMode::Diff => self.output_diff(),
             PeriodicMode::Full => self.output_full(),
         }
     }
+
+    /// Looks up the unit/description registered for a metric name, if any,
+    /// as owned strings ready to embed in a record.
+    fn description_for(&self, name: &str) -> (Option<String>, Option<String>) {
+        let Some(desc) = self.descriptions.get(name) else {
+            return (None, None);
+        };
+        let unit = desc.unit.map(|unit| unit.as_str().to_string());
+        let description = (!desc.description.is_empty()).then(|| desc.description.clone());
+        (unit, description)
+    }
+
+    fn counter_record(&self, key: &MetricKey, value: u64) -> CounterRecord {
+        let (unit, description) = self.description_for(&key.name);
+        CounterRecord {
+            name: key.name.clone(),
+            labels: key.labels.clone(),
+            value,
+            unit,
+            description,
+        }
+    }
+
+    fn gauge_record(&self, key: &MetricKey, value: f64) -> GaugeRecord {
+        let (unit, description) = self.description_for(&key.name);
+        GaugeRecord {
+            name: key.name.clone(),
+            labels: key.labels.clone(),
+            value,
+            unit,
+            description,
+        }
+    }
+
+    fn histogram_record(&self, key: &MetricKey, histogram: &HistogramState) -> HistogramRecord {
+        let (unit, description) = self.description_for(&key.name);
+        // Before the P² warm-up completes, `quantile()` returns `None` — omit
+        // the entry entirely rather than reporting a misleading `0.0`, so
+        // renderers can fall back to something sane (e.g. the observed max)
+        // instead of emitting three identical, bogus estimates.
+        let quantiles = TRACKED_QUANTILES
+            .iter()
+            .filter_map(|(label, p)| histogram.quantile(*p).map(|v| (label.to_string(), v)))
+            .collect::<BTreeMap<_, _>>();
+        HistogramRecord {
+            name: key.name.clone(),
+            labels: key.labels.clone(),
+            avg: histogram.avg().unwrap_or(0.0),
+            std_dev: histogram.std_dev().unwrap_or(0.0),
+            min: histogram.min,
+            max: histogram.max,
+            samples: histogram.num_samples,
+            quantiles,
+            unit,
+            description,
+        }
+    }
+
+    /// Drops any metric that hasn't been updated within `idle_timeout`, so
+    /// long-running processes don't accumulate unbounded (and, for periodic
+    /// `Full` mode, stale) state for high-cardinality labeled metrics.
+    fn purge_idle(&mut self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        let now = Instant::now();
+        let stale: Vec<MetricKey> = self
+            .last_updated
+            .iter()
+            .filter(|(_, &last_updated)| now.duration_since(last_updated) > idle_timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in stale {
+            self.counter_state.remove(&key);
+            self.gauge_state.remove(&key);
+            self.histogram_state.remove(&key);
+            self.counter_updates.remove(&key);
+            self.gauge_updates.remove(&key);
+            self.histogram_updates.remove(&key);
+            self.last_updated.remove(&key);
+        }
+    }
+
     fn output_diff(&mut self) -> Option<String> {
-        let mut logs = String::new();
+        let mut doc = LogDocument::default();
 
-        // Process counter updates
-        for name in self.counter_updates.drain() {
-            if let Some(value) = self.counter_state.get(&name) {
-                logs.push_str(&format!(r#"{{"{}": {}}},"#, name, value));
+        let counter_keys: Vec<MetricKey> = self.counter_updates.drain().collect();
+        for key in &counter_keys {
+            if let Some(&value) = self.counter_state.get(key) {
+                doc.counters.push(self.counter_record(key, value));
             }
         }
 
-        // Process gauge updates
-        for name in self.gauge_updates.drain() {
-            if let Some(value) = self.gauge_state.get(&name) {
-                logs.push_str(&format!(r#"{{"{}": {}}},"#, name, value));
+        let gauge_keys: Vec<MetricKey> = self.gauge_updates.drain().collect();
+        for key in &gauge_keys {
+            if let Some(&value) = self.gauge_state.get(key) {
+                doc.gauges.push(self.gauge_record(key, value));
             }
         }
 
-        // Process histogram updates
-        for name in self.histogram_updates.drain() {
-            if let Some(histogram) = self.histogram_state.get(&name) {
-                let avg = histogram.avg().unwrap_or(0.0);
-                let std_dev = histogram.std_dev().unwrap_or(0.0);
-                logs.push_str(&format!(
-                    r#"{{"{}": {{"avg": {:.2}, "std_dev": {:.2}, "min": {:.2}, "max": {:.2}, "samples": {}}}}},"#,
-                    name, avg, std_dev, histogram.min, histogram.max, histogram.num_samples
-                ));
+        let histogram_keys: Vec<MetricKey> = self.histogram_updates.drain().collect();
+        for key in &histogram_keys {
+            if let Some(histogram) = self.histogram_state.get(key) {
+                doc.histograms.push(self.histogram_record(key, histogram));
             }
         }
 
-        if logs.is_empty() { None } else { Some(logs) }
+        if doc.is_empty() {
+            None
+        } else {
+            Some(self.serializer.serialize(&doc))
+        }
     }
 
     fn output_full(&mut self) -> Option<String> {
-        let mut logs = String::new();
-        // Print all counter states as JSON
-        for (name, value) in &self.counter_state {
-            logs.push_str(&format!(r#"{{"{}": {}}},"#, name, value));
+        let mut doc = LogDocument::default();
+
+        for (key, &value) in &self.counter_state {
+            doc.counters.push(self.counter_record(key, value));
         }
 
-        // Print all gauge states as JSON
-        for (name, value) in &self.gauge_state {
-            logs.push_str(&format!(r#"{{"{}": {}}},"#, name, value));
+        for (key, &value) in &self.gauge_state {
+            doc.gauges.push(self.gauge_record(key, value));
         }
 
-        // Print all histogram states as JSON
-        for (name, histogram) in &self.histogram_state {
-            let avg = histogram.avg().unwrap_or(0.0);
-            let std_dev = histogram.std_dev().unwrap_or(0.0);
-            logs.push_str(&format!(
-                r#"{{"{}": {{"avg": {:.2}, "std_dev": {:.2}, "min": {:.2}, "max": {:.2}, "samples": {}}}}},"#,
-                name, avg, std_dev, histogram.min, histogram.max, histogram.num_samples
-            ));
+        for (key, histogram) in &self.histogram_state {
+            doc.histograms.push(self.histogram_record(key, histogram));
         }
 
-        if logs.is_empty() { None } else { Some(logs) }
+        if doc.is_empty() {
+            None
+        } else {
+            Some(self.serializer.serialize(&doc))
+        }
     }
 }
 
-#[derive(Default)]
 struct HistogramState {
     sum: f64,
     sum_sq: f64,
     num_samples: u64,
     min: f64,
     max: f64,
+    quantiles: Vec<P2Quantile>,
+}
+
+impl Default for HistogramState {
+    fn default() -> Self {
+        Self {
+            sum: 0.0,
+            sum_sq: 0.0,
+            num_samples: 0,
+            min: 0.0,
+            max: 0.0,
+            quantiles: TRACKED_QUANTILES
+                .iter()
+                .map(|(_, p)| P2Quantile::new(*p))
+                .collect(),
+        }
+    }
 }
 
 impl HistogramState {
@@ -142,6 +284,16 @@ impl HistogramState {
             self.max = self.max.max(value);
         }
         self.num_samples += 1;
+
+        for quantile in &mut self.quantiles {
+            quantile.update(value);
+        }
+    }
+
+    /// Returns the current estimate for quantile `p`, or `None` if `p` isn't
+    /// one of the tracked quantiles or too few samples have been observed.
+    fn quantile(&self, p: f64) -> Option<f64> {
+        self.quantiles.iter().find(|q| q.p() == p)?.estimate()
     }
 
     fn std_dev(&self) -> Option<f64> {
@@ -211,4 +363,130 @@ mod tests {
         assert_eq!(histogram.min, 5.0);
         assert_eq!(histogram.max, 25.0);
     }
+
+    #[test]
+    fn test_histogram_state_quantile_unset_before_warmup() {
+        let histogram = HistogramState::default();
+        assert_eq!(histogram.quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_histogram_state_quantile_tracks_median() {
+        let mut histogram = HistogramState::default();
+        for i in 1..=1000 {
+            histogram.update(i as f64);
+        }
+
+        let p50 = histogram.quantile(0.5).unwrap();
+        assert!((p50 - 500.5).abs() < 25.0, "p50 was {p50}");
+    }
+
+    #[test]
+    fn test_metric_key_same_name_different_labels_do_not_collide() {
+        let mut state = MetricsState::new(OutputFormat::Json, None);
+        let mut route_a = BTreeMap::new();
+        route_a.insert("route".to_string(), "/a".to_string());
+        let mut route_b = BTreeMap::new();
+        route_b.insert("route".to_string(), "/b".to_string());
+
+        state.update(MetricsCmd::Counter(CounterCmd::Increment {
+            key: MetricKey {
+                name: "requests".to_string(),
+                labels: route_a,
+            },
+            value: 1,
+        }));
+        state.update(MetricsCmd::Counter(CounterCmd::Increment {
+            key: MetricKey {
+                name: "requests".to_string(),
+                labels: route_b,
+            },
+            value: 5,
+        }));
+
+        assert_eq!(state.counter_state.len(), 2);
+    }
+
+    #[test]
+    fn test_idle_metrics_are_purged_after_timeout() {
+        let mut state = MetricsState::new(OutputFormat::Json, Some(Duration::from_millis(1)));
+        state.update(MetricsCmd::Counter(CounterCmd::Increment {
+            key: MetricKey {
+                name: "requests".to_string(),
+                labels: Default::default(),
+            },
+            value: 1,
+        }));
+        assert_eq!(state.counter_state.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(10));
+        state.purge_idle();
+
+        assert!(state.counter_state.is_empty());
+        assert!(state.last_updated.is_empty());
+    }
+
+    #[test]
+    fn test_immediate_mode_never_expires_metrics() {
+        let mut state = MetricsState::new(OutputFormat::Json, None);
+        state.update(MetricsCmd::Counter(CounterCmd::Increment {
+            key: MetricKey {
+                name: "requests".to_string(),
+                labels: Default::default(),
+            },
+            value: 1,
+        }));
+
+        std::thread::sleep(Duration::from_millis(10));
+        state.purge_idle();
+
+        assert_eq!(state.counter_state.len(), 1);
+    }
+
+    #[test]
+    fn test_describe_includes_unit_and_description_in_json_output() {
+        let mut state = MetricsState::new(OutputFormat::Json, None);
+        state.update(MetricsCmd::Describe(DescribeCmd {
+            name: "bytes_sent".to_string(),
+            unit: Some(metrics::Unit::Bytes),
+            description: "total bytes sent".to_string(),
+        }));
+        state.update(MetricsCmd::Counter(CounterCmd::Increment {
+            key: MetricKey {
+                name: "bytes_sent".to_string(),
+                labels: Default::default(),
+            },
+            value: 42,
+        }));
+
+        let logs = state.output_logs(PeriodicMode::Diff).unwrap();
+        assert!(logs.contains(r#""unit":"bytes""#), "logs were: {logs}");
+        assert!(
+            logs.contains(r#""description":"total bytes sent""#),
+            "logs were: {logs}"
+        );
+    }
+
+    #[test]
+    fn test_output_diff_emits_valid_json() {
+        let mut state = MetricsState::new(OutputFormat::Json, None);
+        state.update(MetricsCmd::Counter(CounterCmd::Increment {
+            key: MetricKey {
+                name: "requests".to_string(),
+                labels: Default::default(),
+            },
+            value: 1,
+        }));
+        state.update(MetricsCmd::Gauge(GaugeCmd::Set {
+            key: MetricKey {
+                name: "queue_depth".to_string(),
+                labels: Default::default(),
+            },
+            value: 2.5,
+        }));
+
+        let logs = state.output_logs(PeriodicMode::Diff).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&logs).expect("logs should be valid JSON");
+        assert_eq!(parsed["gauges"][0]["value"], 2.5);
+    }
 }
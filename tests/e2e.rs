@@ -1,4 +1,4 @@
-use metrics_logger::{MetricsLogger, metrics};
+use metrics_logger::{LogMode, MetricsLogger, OutputFormat, metrics};
 use std::time::Duration;
 
 #[test]
@@ -6,7 +6,11 @@ fn test_metrics_logger_integration() {
     simple_logger::init_with_level(log::Level::Debug).unwrap();
 
     let recorder = MetricsLogger::new(
-        1,
+        LogMode::Periodic {
+            interval_secs: 1,
+            idle_timeout: Duration::from_secs(60),
+        },
+        OutputFormat::Json,
         |logs| log::debug!("\n{}", logs),
         |err| log::error!("MetricsLogger error: {}", err),
     );
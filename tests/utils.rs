@@ -1,4 +1,4 @@
-use metrics_logger::{LogMode, MetricsLogger, metrics};
+use metrics_logger::{LogMode, MetricsLogger, OutputFormat, metrics};
 use std::time::Duration;
 
 pub fn metrics_logger_test(mode: LogMode) {
@@ -6,6 +6,7 @@ pub fn metrics_logger_test(mode: LogMode) {
 
     let recorder = MetricsLogger::new(
         mode,
+        OutputFormat::Json,
         |logs| log::debug!("\n{}", logs),
         |err| log::error!("MetricsLogger error: {}", err),
     );